@@ -0,0 +1,186 @@
+//! Bounded, content-addressed cache of chunks already fetched from the database
+//!
+//! Noms chunks are immutable and named by their `Hash`, so a cached entry
+//! never needs to be invalidated -- the only reason to ever drop one is to
+//! keep the cache within its capacity, in which case the least-recently-used
+//! entry goes first.
+//!
+//! `ChunkCache` wraps an inner `ChunkStore` and is itself a `ChunkStore`, so
+//! any code that resolves a `Hash` through it -- directly, or transitively
+//! while walking a sequence tree -- benefits from the cache automatically.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use hash::Hash;
+use error::Error;
+use super::{Chunk, ChunkStore};
+
+/// Chunks are rarely tiny, so keep the default bound modest.
+const DEFAULT_CAPACITY: usize = 1024;
+
+pub(crate) struct ChunkCache {
+    inner: Box<ChunkStore>,
+    capacity: usize,
+    // front = least recently used, back = most recently used
+    order: RefCell<VecDeque<Hash>>,
+    entries: RefCell<HashMap<Hash, Chunk>>,
+}
+
+impl ChunkCache {
+    pub fn new(inner: Box<ChunkStore>) -> Self {
+        Self::with_capacity(inner, DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(inner: Box<ChunkStore>, capacity: usize) -> Self {
+        Self {
+            inner,
+            capacity,
+            order: RefCell::new(VecDeque::new()),
+            entries: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn touch(&self, hash: Hash) -> Option<Chunk> {
+        let chunk = self.entries.borrow().get(&hash).cloned();
+        if chunk.is_some() {
+            let mut order = self.order.borrow_mut();
+            order.retain(|h| *h != hash);
+            order.push_back(hash);
+        }
+        chunk
+    }
+
+    fn insert(&self, hash: Hash, chunk: Chunk) {
+        let mut entries = self.entries.borrow_mut();
+        let mut order = self.order.borrow_mut();
+        if entries.len() >= self.capacity && !entries.contains_key(&hash) {
+            if let Some(lru) = order.pop_front() {
+                entries.remove(&lru);
+            }
+        }
+        entries.insert(hash, chunk);
+        // `hash` may already be present (e.g. re-`put`ting identical
+        // content), in which case it must be removed from its old position
+        // first -- otherwise `order` grows a duplicate entry and a later
+        // `pop_front` can evict a still-live key, or pop a ghost, letting
+        // `entries` exceed `capacity`. Same dedupe `touch` already does.
+        order.retain(|h| *h != hash);
+        order.push_back(hash);
+    }
+}
+
+impl ChunkStore for ChunkCache {
+    /// A hit is always trusted as-is, since chunks never change once
+    /// written; a miss falls through to the wrapped store and caches the
+    /// result before returning it.
+    fn get(&self, hash: Hash) -> Result<Option<Chunk>, Error> {
+        if let Some(chunk) = self.touch(hash) {
+            return Ok(Some(chunk));
+        }
+        match self.inner.get(hash)? {
+            Some(chunk) => {
+                self.insert(hash, chunk.clone());
+                Ok(Some(chunk))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn put(&self, chunk: Chunk) -> Result<Hash, Error> {
+        let hash = self.inner.put(chunk.clone())?;
+        self.insert(hash, chunk);
+        Ok(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    /// A `ChunkStore` that counts every `get` it services, so tests can
+    /// assert the cache actually avoided a re-fetch. The counter is shared
+    /// via `Rc` so it stays readable after the store is boxed into a cache.
+    struct CountingStore {
+        chunk: Chunk,
+        fetches: Rc<RefCell<usize>>,
+    }
+
+    impl ChunkStore for CountingStore {
+        fn get(&self, _hash: Hash) -> Result<Option<Chunk>, Error> {
+            *self.fetches.borrow_mut() += 1;
+            Ok(Some(self.chunk.clone()))
+        }
+        fn put(&self, _chunk: Chunk) -> Result<Hash, Error> {
+            unimplemented!()
+        }
+    }
+
+    fn hash_of(byte: u8) -> Hash {
+        Hash::of(&vec![byte])
+    }
+
+    #[test]
+    fn repeated_get_hits_the_cache_not_the_store() {
+        let chunk = Chunk::new(vec![1, 2, 3]);
+        let fetches = Rc::new(RefCell::new(0));
+        let store = CountingStore{ chunk: chunk.clone(), fetches: fetches.clone() };
+        let cache = ChunkCache::new(Box::new(store));
+        let hash = hash_of(1);
+
+        assert_eq!(Some(chunk.clone()), cache.get(hash).unwrap());
+        assert_eq!(Some(chunk), cache.get(hash).unwrap());
+        assert_eq!(1, *fetches.borrow());
+    }
+
+    #[test]
+    fn evicts_least_recently_used_once_over_capacity() {
+        let store = CountingStore{ chunk: Chunk::new(vec![0]), fetches: Rc::new(RefCell::new(0)) };
+        let cache = ChunkCache::with_capacity(Box::new(store), 2);
+
+        cache.insert(hash_of(1), Chunk::new(vec![1]));
+        cache.insert(hash_of(2), Chunk::new(vec![2]));
+        cache.insert(hash_of(3), Chunk::new(vec![3]));
+
+        assert_eq!(2, cache.entries.borrow().len());
+        assert!(!cache.entries.borrow().contains_key(&hash_of(1)));
+        assert!(cache.entries.borrow().contains_key(&hash_of(2)));
+        assert!(cache.entries.borrow().contains_key(&hash_of(3)));
+    }
+
+    #[test]
+    fn touching_an_entry_protects_it_from_eviction() {
+        let store = CountingStore{ chunk: Chunk::new(vec![0]), fetches: Rc::new(RefCell::new(0)) };
+        let cache = ChunkCache::with_capacity(Box::new(store), 2);
+
+        cache.insert(hash_of(1), Chunk::new(vec![1]));
+        cache.insert(hash_of(2), Chunk::new(vec![2]));
+        cache.touch(hash_of(1));
+        cache.insert(hash_of(3), Chunk::new(vec![3]));
+
+        assert!(cache.entries.borrow().contains_key(&hash_of(1)));
+        assert!(!cache.entries.borrow().contains_key(&hash_of(2)));
+    }
+
+    #[test]
+    fn reinserting_an_already_cached_entry_does_not_duplicate_its_order_slot() {
+        let store = CountingStore{ chunk: Chunk::new(vec![0]), fetches: Rc::new(RefCell::new(0)) };
+        let cache = ChunkCache::with_capacity(Box::new(store), 2);
+
+        cache.insert(hash_of(1), Chunk::new(vec![1]));
+        cache.insert(hash_of(2), Chunk::new(vec![2]));
+        // Re-inserting an entry already at capacity (e.g. re-`put`ting
+        // identical content) must not grow `order` past one slot per entry.
+        cache.insert(hash_of(1), Chunk::new(vec![1]));
+
+        assert_eq!(2, cache.order.borrow().len());
+        assert_eq!(2, cache.entries.borrow().len());
+
+        cache.insert(hash_of(3), Chunk::new(vec![3]));
+
+        assert_eq!(2, cache.entries.borrow().len());
+        assert!(!cache.entries.borrow().contains_key(&hash_of(2)));
+        assert!(cache.entries.borrow().contains_key(&hash_of(1)));
+        assert!(cache.entries.borrow().contains_key(&hash_of(3)));
+    }
+}
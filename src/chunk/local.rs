@@ -0,0 +1,37 @@
+//! On-disk `ChunkStore` backed by RocksDB
+//!
+//! Chunks are content-addressed, so this store is a plain key/value table
+//! keyed by hash -- `put` is idempotent and there is no separate index or
+//! manifest to keep in sync.
+
+use rocksdb::DB;
+use hash::Hash;
+use error::Error;
+use super::Chunk;
+use super::store::ChunkStore;
+
+pub(crate) struct LocalChunkStore {
+    db: DB,
+}
+
+impl LocalChunkStore {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let db = DB::open_default(path)?;
+        Ok(Self{ db })
+    }
+}
+
+impl ChunkStore for LocalChunkStore {
+    fn get(&self, hash: Hash) -> Result<Option<Chunk>, Error> {
+        match self.db.get(&hash.raw_bytes())? {
+            Some(bytes) => Ok(Some(Chunk::new(bytes.to_vec()))),
+            None => Ok(None),
+        }
+    }
+
+    fn put(&self, chunk: Chunk) -> Result<Hash, Error> {
+        let hash = Hash::of(chunk.data());
+        self.db.put(&hash.raw_bytes(), chunk.data())?;
+        Ok(hash)
+    }
+}
@@ -1,10 +1,20 @@
 //! Handles raw data from the database
 
+mod cache;
+mod store;
+mod local;
+mod sequence;
+pub(crate) use self::cache::ChunkCache;
+pub(crate) use self::store::ChunkStore;
+pub(crate) use self::local::LocalChunkStore;
+pub(crate) use self::sequence::{Sequence, MetaEntry, SequenceIter, MapSequenceIter};
+
 use hyper;
+use error::Error;
 use hash::{Hash, BYTE_LEN};
 use std::cell::Cell;
 use byteorder::{NetworkEndian, ByteOrder};
-use value::{Value, Kind, Ref, IntoNoms, FromNoms};
+use value::{Value, Kind, Ref, IntoNoms, FromNoms, Number};
 use std::mem::transmute;
 use std::collections::{HashMap, HashSet};
 
@@ -60,10 +70,10 @@ impl<'a> ChunkReader<'a> {
         n
     }
 
-    pub fn extract_u16(&self) -> u32 {
+    pub fn extract_u16(&self) -> u16 {
         let offset = self.offset.get();
-        let n = NetworkEndian::read_u32(&self.chunk.0[offset..offset + 8]);
-        self.offset.set(offset + 8);
+        let n = NetworkEndian::read_u16(&self.chunk.0[offset..offset + 2]);
+        self.offset.set(offset + 2);
         n
     }
 
@@ -74,6 +84,11 @@ impl<'a> ChunkReader<'a> {
         n
     }
 
+    /// Decodes a struct's name and fields. If the same field name appears
+    /// twice, the later occurrence wins -- the same "last entry wins"
+    /// semantics as folding the fields through `HashMap::from_iter`. Use
+    /// [`extract_struct_strict`](ChunkReader::extract_struct_strict) to
+    /// reject that ambiguity instead.
     pub fn extract_struct(&self) -> (String, HashMap<String, Chunk>) {
         assert_eq!(Kind::Struct, self.extract_kind());
         let len = self.extract_u8();
@@ -88,6 +103,28 @@ impl<'a> ChunkReader<'a> {
         (name, props)
     }
 
+    /// Like [`extract_struct`](ChunkReader::extract_struct), but rejects a
+    /// struct that encodes the same field name twice instead of silently
+    /// keeping the last one. Duplicate-key encodings are a classic source
+    /// of parser-differential bugs, so callers decoding untrusted data
+    /// should prefer this over the lenient default.
+    pub fn extract_struct_strict(&self) -> Result<(String, HashMap<String, Chunk>), Error> {
+        assert_eq!(Kind::Struct, self.extract_kind());
+        let len = self.extract_u8();
+        let name = String::from_utf8(self.extract_raw(len as usize).into_data()).unwrap();
+        let prop_count = self.extract_u8() as usize;
+        let mut props = HashMap::with_capacity(prop_count);
+        for _ in 0..prop_count {
+            let key = self.extract_raw_string();
+            let value = self.extract_chunk();
+            if props.contains_key(&key) {
+                return Err(Error::DuplicateKey);
+            }
+            props.insert(key, value);
+        }
+        Ok((name, props))
+    }
+
     fn extract_raw_string(&self) -> String {
         let len = self.extract_u8();
         let offset = self.offset.get();
@@ -115,9 +152,26 @@ impl<'a> ChunkReader<'a> {
                 self.extract_struct();
                 Chunk::new(self.chunk.0[offset..self.offset.get()].to_vec())
             }
-            Kind::Set => {
+            Kind::Set | Kind::List => {
+                self.extract_sequence(1);
+                Chunk::new(self.chunk.0[offset..self.offset.get()].to_vec())
+            }
+            Kind::Map => {
+                self.extract_sequence(2);
+                Chunk::new(self.chunk.0[offset..self.offset.get()].to_vec())
+            }
+            Kind::Boolean => {
+                self.extract_u8();
+                Chunk::new(self.chunk.0[offset..self.offset.get()].to_vec())
+            }
+            Kind::Blob => {
                 self.offset.set(offset);
-                self.extract_set::<Value>();
+                self.extract_blob();
+                Chunk::new(self.chunk.0[offset..self.offset.get()].to_vec())
+            }
+            Kind::Number => {
+                self.offset.set(offset);
+                self.extract_number();
                 Chunk::new(self.chunk.0[offset..self.offset.get()].to_vec())
             }
             _ => unimplemented!(),
@@ -135,26 +189,122 @@ impl<'a> ChunkReader<'a> {
         unsafe{ transmute(self.extract_u8()) }
     }
 
-    pub fn extract_map<K: FromNoms + Eq + ::std::hash::Hash, V: FromNoms>(&self) -> HashMap<K, V> {
+    /// Decodes a map's entries. If the same key appears twice, the later
+    /// occurrence wins -- the same "last entry wins" semantics as folding
+    /// the entries through `HashMap::from_iter`. Use
+    /// [`extract_map_strict`](ChunkReader::extract_map_strict) to reject
+    /// that ambiguity instead. Errs, rather than panicking, on a multi-chunk
+    /// (meta) map -- this reader has no `ChunkStore` to resolve the tree's
+    /// children, so callers who expect a tree-shaped collection must walk it
+    /// themselves via [`Value::iter_entries`](::value::Value::iter_entries).
+    pub fn extract_map<K: FromNoms + Eq + ::std::hash::Hash, V: FromNoms>(&self) -> Result<HashMap<K, V>, Error> {
         assert_eq!(Kind::Map, self.extract_kind());
-        let mut map = HashMap::new();
-        let entries = self.extract_u16();
-        for _ in 0..entries {
-            let key = self.extract_chunk();
-            let value = self.extract_chunk();
-            map.insert(K::from_noms(&key.into_value()), V::from_noms(&value.into_value()));
+        let chunks = match self.extract_sequence(2) {
+            Sequence::Leaf(chunks) => chunks,
+            Sequence::Meta(_) => return Err(Error::MultiChunkSequence),
+        };
+        let mut map = HashMap::with_capacity(chunks.len() / 2);
+        let mut chunks = chunks.into_iter();
+        while let (Some(key), Some(value)) = (chunks.next(), chunks.next()) {
+            map.insert(K::from_noms(&key.into_value())?, V::from_noms(&value.into_value())?);
         }
-        map
+        Ok(map)
     }
 
-    pub fn extract_set<V: FromNoms + ::std::hash::Hash + Eq>(&self) -> HashSet<V> {
+    /// Like [`extract_map`](ChunkReader::extract_map), but rejects a map
+    /// that encodes the same key twice instead of silently keeping the
+    /// last one. Callers decoding untrusted Noms data (e.g. from the HTTP
+    /// endpoint) can use this to fail closed on ambiguous input.
+    pub fn extract_map_strict<K: FromNoms + Eq + ::std::hash::Hash, V: FromNoms>(&self) -> Result<HashMap<K, V>, Error> {
+        assert_eq!(Kind::Map, self.extract_kind());
+        let chunks = match self.extract_sequence(2) {
+            Sequence::Leaf(chunks) => chunks,
+            Sequence::Meta(_) => return Err(Error::MultiChunkSequence),
+        };
+        let mut map = HashMap::with_capacity(chunks.len() / 2);
+        let mut chunks = chunks.into_iter();
+        while let (Some(key), Some(value)) = (chunks.next(), chunks.next()) {
+            let key = K::from_noms(&key.into_value())?;
+            let value = V::from_noms(&value.into_value())?;
+            if map.contains_key(&key) {
+                return Err(Error::DuplicateKey);
+            }
+            map.insert(key, value);
+        }
+        Ok(map)
+    }
+
+    /// Errs, rather than panicking, on a multi-chunk (meta) set -- see
+    /// [`extract_map`](ChunkReader::extract_map) for why; use
+    /// [`Value::iter`](::value::Value::iter) to walk a tree-shaped set.
+    pub fn extract_set<V: FromNoms + ::std::hash::Hash + Eq>(&self) -> Result<HashSet<V>, Error> {
         assert_eq!(Kind::Set, self.extract_kind());
-        let len = self.extract_u16();
-        let mut set = HashSet::with_capacity(len as usize);
-        for _ in 0..len {
-            set.insert(V::from_noms(&self.extract_chunk().into_value()));
+        match self.extract_sequence(1) {
+            Sequence::Leaf(chunks) => chunks.into_iter().map(|c| V::from_noms(&c.into_value())).collect(),
+            Sequence::Meta(_) => Err(Error::MultiChunkSequence),
+        }
+    }
+
+    /// Decodes the body of a `Set`, `Map` or `List` chunk (the kind tag is
+    /// assumed already consumed): a leaf's entries if this chunk is at tree
+    /// level 0, or a meta chunk's child pointers otherwise. `arity` is the
+    /// number of chunks a single entry occupies in a leaf -- 1 for `Set`/
+    /// `List`, 2 for `Map` (alternating key, value) -- so `count` always
+    /// means "number of entries" regardless of which kind is being decoded.
+    /// Used by `SequenceCursor` to lazily walk arbitrarily large
+    /// collections.
+    pub fn extract_sequence(&self, arity: usize) -> Sequence {
+        let level = self.extract_u8();
+        let count = self.extract_u32() as usize;
+        if level == 0 {
+            let mut leaves = Vec::with_capacity(count * arity);
+            for _ in 0..count * arity {
+                leaves.push(self.extract_chunk());
+            }
+            Sequence::Leaf(leaves)
+        } else {
+            let mut entries = Vec::with_capacity(count);
+            for _ in 0..count {
+                let r = self.extract_ref();
+                self.extract_chunk(); // ordering key, unused until seek-by-key is implemented
+                self.extract_u32(); // num leaves, ditto
+                entries.push(MetaEntry{ r });
+            }
+            Sequence::Meta(entries)
+        }
+    }
+
+    pub fn extract_bool(&self) -> bool {
+        assert_eq!(Kind::Boolean, self.extract_kind());
+        self.extract_u8() != 0
+    }
+
+    pub fn extract_blob(&self) -> Vec<u8> {
+        assert_eq!(Kind::Blob, self.extract_kind());
+        let len = self.extract_u32();
+        self.extract_raw(len as usize).into_data()
+    }
+
+    /// Decodes an arbitrary-precision `Number`: a sign byte, a
+    /// length-prefixed big-endian magnitude, and a base-10 exponent.
+    pub fn extract_number(&self) -> Number {
+        assert_eq!(Kind::Number, self.extract_kind());
+        let negative = self.extract_u8() != 0;
+        let len = self.extract_u32() as usize;
+        let bytes = self.extract_raw(len).into_data();
+        let exponent = self.extract_u32() as i32;
+        Number::from_be_bytes(negative, &bytes, exponent)
+    }
+
+    /// Decodes a single-chunk `List`. Errs, rather than panicking, on a
+    /// multi-chunk (meta) list -- use [`Value::iter`](::value::Value::iter)
+    /// for those instead.
+    pub fn extract_list<V: FromNoms>(&self) -> Result<Vec<V>, Error> {
+        assert_eq!(Kind::List, self.extract_kind());
+        match self.extract_sequence(1) {
+            Sequence::Leaf(chunks) => chunks.into_iter().map(|c| V::from_noms(&c.into_value())).collect(),
+            Sequence::Meta(_) => Err(Error::MultiChunkSequence),
         }
-        set
     }
 
     pub fn extract_raw(&self, len: usize) -> Chunk {
@@ -207,13 +357,25 @@ impl ChunkWriter {
             .write_hash(r.hash())
     }
 
+    /// Writes a `Map`'s entries in canonical order (by encoded key bytes,
+    /// ascending) rather than `HashMap`'s unspecified iteration order, so the
+    /// same logical map always produces the same chunk -- and therefore the
+    /// same content hash -- regardless of insertion history. This matters
+    /// for the optimistic CAS write path, which depends on client and server
+    /// agreeing on a chunk's hash.
     pub fn write_map<K: IntoNoms + Eq + ::std::hash::Hash, V: IntoNoms>(mut self, map: &HashMap<K, V>) -> Self {
+        let mut entries: Vec<(Value, Value)> = map.iter()
+            .map(|(k, v)| (k.into_noms(), v.into_noms()))
+            .collect();
+        entries.sort_by(|&(ref a, _), &(ref b, _)| a.raw().cmp(b.raw()));
+
         self = self.write_kind(Kind::Map)
-            .write_u16(map.len() as u16);
-        for (k, v) in map {
+            .write_u8(0) // tree level: always written as a single leaf chunk
+            .write_u32(entries.len() as u32); // count of entries, not of chunks -- see extract_sequence
+        for (key, value) in entries {
             self = self
-                .write_value(k.into_noms())
-                .write_value(v.into_noms())
+                .write_value(key)
+                .write_value(value)
         }
         self
     }
@@ -222,13 +384,237 @@ impl ChunkWriter {
         self.write_bytes(value.into_raw())
     }
 
+    pub fn write_bool(self, v: bool) -> Self {
+        self.write_kind(Kind::Boolean)
+            .write_u8(v as u8)
+    }
+
+    pub fn write_blob(self, bytes: &[u8]) -> Self {
+        self.write_kind(Kind::Blob)
+            .write_u32(bytes.len() as u32)
+            .write_bytes(bytes.to_vec())
+    }
+
+    /// Writes an arbitrary-precision `Number` as a sign byte, a
+    /// length-prefixed big-endian magnitude, and a base-10 exponent.
+    pub fn write_number(self, n: &Number) -> Self {
+        let (negative, bytes) = n.to_be_bytes();
+        self.write_kind(Kind::Number)
+            .write_u8(negative as u8)
+            .write_u32(bytes.len() as u32)
+            .write_bytes(bytes)
+            .write_u32(n.exponent() as u32)
+    }
+
+    /// Writes a `List` as a single leaf chunk. This always produces a
+    /// tree-level-0 (leaf) chunk, however long the list; it never splits a
+    /// list into a sequence tree.
+    pub fn write_list<V: IntoNoms>(mut self, list: &[V]) -> Self {
+        self = self.write_kind(Kind::List)
+            .write_u8(0)
+            .write_u32(list.len() as u32);
+        for v in list {
+            self = self.write_value(v.into_noms());
+        }
+        self
+    }
+
     pub fn write_string(self, string: &str) -> Self {
         self.write_kind(Kind::String)
-            .write_u8(string.len() as u8)
+            .write_raw_string(string)
+    }
+
+    fn write_raw_string(self, string: &str) -> Self {
+        self.write_u8(string.len() as u8)
             .write_bytes(string.as_bytes().to_vec())
     }
 
+    /// Writes a struct's name and fields. Each value in `props` must
+    /// already be a complete, self-describing chunk (e.g. as returned by
+    /// `ChunkReader::extract_chunk`), matching the layout
+    /// `ChunkReader::extract_struct` expects to read back. Fields are
+    /// written in canonical order (by name, ascending) rather than
+    /// `HashMap`'s unspecified iteration order, so the same logical struct
+    /// always hashes identically -- required by the optimistic CAS write
+    /// path, which depends on client and server agreeing on a chunk's hash.
+    pub fn write_struct(mut self, name: &str, props: &HashMap<String, Chunk>) -> Self {
+        let mut props: Vec<(&String, &Chunk)> = props.iter().collect();
+        props.sort_by(|&(a, _), &(b, _)| a.cmp(b));
+
+        self = self.write_kind(Kind::Struct)
+            .write_raw_string(name)
+            .write_u8(props.len() as u8);
+        for (key, value) in props {
+            self = self.write_raw_string(key)
+                .write_bytes(value.data().clone());
+        }
+        self
+    }
+
     pub fn finish(self) -> Chunk {
         Chunk::new(self.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use value::Value;
+
+    /// A `ChunkStore` that is never actually called -- single-chunk (level
+    /// 0) sequences never resolve a child ref.
+    struct UnreachableStore;
+    impl ChunkStore for UnreachableStore {
+        fn get(&self, _hash: Hash) -> Result<Option<Chunk>, Error> {
+            unreachable!("single-chunk sequence should not resolve any ref")
+        }
+        fn put(&self, _chunk: Chunk) -> Result<Hash, Error> {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn map_round_trips_through_write_and_extract() {
+        let mut map = HashMap::new();
+        map.insert("a".to_string(), 1i64);
+        map.insert("b".to_string(), 2i64);
+
+        let chunk = Chunk::writer().write_map(&map).finish();
+        let decoded: HashMap<String, i64> = chunk.reader().extract_map().unwrap();
+
+        assert_eq!(map, decoded);
+    }
+
+    #[test]
+    fn extract_map_errs_on_a_multi_chunk_meta_sequence() {
+        let chunk = Chunk::writer()
+            .write_kind(Kind::Map)
+            .write_u8(1) // tree level > 0: a meta chunk, not a leaf
+            .write_u32(0)
+            .finish();
+
+        assert!(chunk.reader().extract_map::<String, i64>().is_err());
+    }
+
+    #[test]
+    fn map_entries_round_trip_through_iter_entries() {
+        let mut map = HashMap::new();
+        map.insert("a".to_string(), 1i64);
+        map.insert("b".to_string(), 2i64);
+
+        let chunk = Chunk::writer().write_map(&map).finish();
+        let value = Value(chunk);
+        let store = UnreachableStore;
+        let decoded: HashMap<String, i64> = value.iter_entries(&store)
+            .map(|(k, v)| (String::from_noms(&k).unwrap(), i64::from_noms(&v).unwrap()))
+            .collect();
+
+        assert_eq!(map, decoded);
+    }
+
+    #[test]
+    fn map_encoding_is_canonical_regardless_of_insertion_order() {
+        let mut forward = HashMap::new();
+        forward.insert("a".to_string(), 1i64);
+        forward.insert("b".to_string(), 2i64);
+        forward.insert("c".to_string(), 3i64);
+
+        let mut backward = HashMap::new();
+        backward.insert("c".to_string(), 3i64);
+        backward.insert("b".to_string(), 2i64);
+        backward.insert("a".to_string(), 1i64);
+
+        let forward = Chunk::writer().write_map(&forward).finish();
+        let backward = Chunk::writer().write_map(&backward).finish();
+        assert_eq!(forward, backward);
+    }
+
+    #[test]
+    fn struct_encoding_is_canonical_regardless_of_insertion_order() {
+        let mut forward = HashMap::new();
+        forward.insert("a".to_string(), 1i64.into_noms().into_raw());
+        forward.insert("b".to_string(), 2i64.into_noms().into_raw());
+        let forward: HashMap<String, Chunk> = forward.into_iter().map(|(k, v)| (k, Chunk::new(v))).collect();
+
+        let mut backward = HashMap::new();
+        backward.insert("b".to_string(), 2i64.into_noms().into_raw());
+        backward.insert("a".to_string(), 1i64.into_noms().into_raw());
+        let backward: HashMap<String, Chunk> = backward.into_iter().map(|(k, v)| (k, Chunk::new(v))).collect();
+
+        let forward = Chunk::writer().write_struct("S", &forward).finish();
+        let backward = Chunk::writer().write_struct("S", &backward).finish();
+        assert_eq!(forward, backward);
+    }
+
+    #[test]
+    fn list_round_trips_through_write_list_and_extract_list() {
+        let list: Vec<i64> = vec![1, 2, 3];
+        let chunk = Chunk::writer().write_list(&list).finish();
+        let decoded: Vec<i64> = chunk.reader().extract_list().unwrap();
+
+        assert_eq!(list, decoded);
+    }
+
+    #[test]
+    fn extract_struct_strict_rejects_duplicate_field_names() {
+        let chunk = Chunk::writer()
+            .write_kind(Kind::Struct)
+            .write_raw_string("S")
+            .write_u8(2)
+            .write_raw_string("x")
+            .write_bytes(1i64.into_noms().into_raw())
+            .write_raw_string("x")
+            .write_bytes(2i64.into_noms().into_raw())
+            .finish();
+
+        assert!(chunk.reader().extract_struct_strict().is_err());
+    }
+
+    #[test]
+    fn extract_struct_is_lenient_and_keeps_the_last_duplicate() {
+        let chunk = Chunk::writer()
+            .write_kind(Kind::Struct)
+            .write_raw_string("S")
+            .write_u8(2)
+            .write_raw_string("x")
+            .write_bytes(1i64.into_noms().into_raw())
+            .write_raw_string("x")
+            .write_bytes(2i64.into_noms().into_raw())
+            .finish();
+
+        let (name, props) = chunk.reader().extract_struct();
+        assert_eq!("S", name);
+        assert_eq!(2i64, i64::from_noms(&props["x"].clone().into_value()).unwrap());
+    }
+
+    #[test]
+    fn extract_map_strict_rejects_duplicate_keys() {
+        let chunk = Chunk::writer()
+            .write_kind(Kind::Map)
+            .write_u8(0)
+            .write_u32(2)
+            .write_value("x".to_string().into_noms())
+            .write_value(1i64.into_noms())
+            .write_value("x".to_string().into_noms())
+            .write_value(2i64.into_noms())
+            .finish();
+
+        assert!(chunk.reader().extract_map_strict::<String, i64>().is_err());
+    }
+
+    #[test]
+    fn extract_map_is_lenient_and_keeps_the_last_duplicate() {
+        let chunk = Chunk::writer()
+            .write_kind(Kind::Map)
+            .write_u8(0)
+            .write_u32(2)
+            .write_value("x".to_string().into_noms())
+            .write_value(1i64.into_noms())
+            .write_value("x".to_string().into_noms())
+            .write_value(2i64.into_noms())
+            .finish();
+
+        let map: HashMap<String, i64> = chunk.reader().extract_map().unwrap();
+        assert_eq!(Some(&2i64), map.get("x"));
+    }
+}
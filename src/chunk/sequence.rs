@@ -0,0 +1,121 @@
+//! Lazy traversal of Noms sequence trees (`Set`, `Map` and `List`)
+//!
+//! Large Noms collections are stored as a tree of chunks: a *leaf* chunk
+//! holds the entries themselves, while a *meta* chunk holds an ordered array
+//! of `(Ref, orderingKey, numLeaves)` tuples pointing at child chunks.
+//! Ordering keys are monotonic within a meta sequence, so a cursor can walk
+//! the tree resolving one child at a time, rather than ever materializing it
+//! in full.
+//!
+//! Resolving a child `Ref` goes through whatever `ChunkStore` the cursor was
+//! built with; passing a `ChunkCache` (or anything else wrapping one) means
+//! that walk is served from cache rather than re-fetching every child on
+//! every traversal.
+
+use std::collections::VecDeque;
+use value::{Value, Ref};
+use error::Error;
+use super::{Chunk, ChunkStore};
+
+/// One entry of a meta sequence chunk.
+pub(crate) struct MetaEntry {
+    pub r: Ref,
+}
+
+/// The decoded body of a single sequence chunk.
+pub(crate) enum Sequence {
+    /// A leaf chunk's entries, in order. For `Map`, entries alternate
+    /// key, value, key, value, ...
+    Leaf(Vec<Chunk>),
+    /// A meta chunk's pointers to child chunks.
+    Meta(Vec<MetaEntry>),
+}
+
+/// Lazily iterates the leaves of a sequence tree, resolving child chunks
+/// through a `ChunkStore` only as the cursor advances. Once a resolution
+/// fails, iteration ends rather than panicking.
+pub(crate) struct SequenceCursor<'a> {
+    store: &'a ChunkStore,
+    /// Chunks per entry in a leaf: 1 for `Set`/`List`, 2 for `Map`.
+    arity: usize,
+    pending: VecDeque<Ref>,
+    leaves: VecDeque<Chunk>,
+}
+
+impl<'a> SequenceCursor<'a> {
+    pub fn new(store: &'a ChunkStore, arity: usize, root: Sequence) -> Self {
+        let mut cursor = SequenceCursor {
+            store,
+            arity,
+            pending: VecDeque::new(),
+            leaves: VecDeque::new(),
+        };
+        cursor.push(root);
+        cursor
+    }
+
+    fn push(&mut self, sequence: Sequence) {
+        match sequence {
+            Sequence::Leaf(chunks) => self.leaves.extend(chunks),
+            Sequence::Meta(entries) => self.pending.extend(entries.into_iter().map(|entry| entry.r)),
+        }
+    }
+
+    /// Pulls child chunks through the store until either a leaf is ready or
+    /// the tree is exhausted.
+    fn advance(&mut self) -> Result<bool, Error> {
+        while self.leaves.is_empty() {
+            let r = match self.pending.pop_front() {
+                Some(r) => r,
+                None => return Ok(false),
+            };
+            let chunk = self.store.get(r.hash())?.ok_or_else(|| Error::MissingChunk(r.hash()))?;
+            let sequence = chunk.reader().extract_sequence(self.arity);
+            self.push(sequence);
+        }
+        Ok(true)
+    }
+
+    fn next_chunk(&mut self) -> Option<Chunk> {
+        match self.advance() {
+            Ok(true) => self.leaves.pop_front(),
+            _ => None,
+        }
+    }
+}
+
+/// Iterates the elements of a `Set` or `List` value.
+pub(crate) struct SequenceIter<'a>(SequenceCursor<'a>);
+
+impl<'a> SequenceIter<'a> {
+    pub fn new(store: &'a ChunkStore, root: Sequence) -> Self {
+        SequenceIter(SequenceCursor::new(store, 1, root))
+    }
+}
+
+impl<'a> Iterator for SequenceIter<'a> {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        self.0.next_chunk().map(Chunk::into_value)
+    }
+}
+
+/// Iterates the entries of a `Map` value as `(key, value)` pairs.
+pub(crate) struct MapSequenceIter<'a>(SequenceCursor<'a>);
+
+impl<'a> MapSequenceIter<'a> {
+    pub fn new(store: &'a ChunkStore, root: Sequence) -> Self {
+        MapSequenceIter(SequenceCursor::new(store, 2, root))
+    }
+}
+
+impl<'a> Iterator for MapSequenceIter<'a> {
+    type Item = (Value, Value);
+
+    fn next(&mut self) -> Option<(Value, Value)> {
+        let key = self.0.next_chunk()?;
+        let value = self.0.next_chunk()?;
+        Some((key.into_value(), value.into_value()))
+    }
+}
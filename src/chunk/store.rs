@@ -0,0 +1,18 @@
+//! Pluggable chunk storage backends
+//!
+//! `Database` reads and writes chunks through whatever `ChunkStore` it is
+//! given, so its chunks can live on a remote Noms server or in a local
+//! on-disk store. The root ref is not kept in a `ChunkStore`: `Database`
+//! still builds an HTTP `Client` and reads and updates the root through it,
+//! so it always needs a Noms server. Because chunks are
+//! content-addressed, `put` is idempotent and `get` never needs cache
+//! coherency logic -- a given hash always names the same bytes.
+
+use hash::Hash;
+use error::Error;
+use super::Chunk;
+
+pub(crate) trait ChunkStore {
+    fn get(&self, hash: Hash) -> Result<Option<Chunk>, Error>;
+    fn put(&self, chunk: Chunk) -> Result<Hash, Error>;
+}
@@ -1,52 +1,345 @@
 /// Defines a database that is backed by a Noms HTTP database
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::cell::RefCell;
 use std::rc::Rc;
 use super::CommitOptions;
-use value::{Value, Ref, FromNoms};
+use value::{Value, Ref, FromNoms, Commit, Kind};
 use dataset::Dataset;
 use error::Error;
+use hash::Hash;
 use http::Client;
+use chunk::{Chunk, ChunkCache, ChunkStore};
 use InnerNoms;
 
+/// Whether a staged chunk has already been written to the backing store.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Staged {
+    Clean,
+    Dirty,
+}
+
+/// Chunks produced by this `Database` (e.g. while building a commit) but
+/// not yet flushed to the store, keyed by content hash so re-staging an
+/// already-staged chunk is a no-op.
+#[derive(Clone)]
+struct Overlay {
+    chunks: RefCell<HashMap<Hash, (Chunk, Staged)>>,
+}
+
+impl Overlay {
+    fn new() -> Self {
+        Overlay{ chunks: RefCell::new(HashMap::new()) }
+    }
+
+    /// Stages `chunk` as dirty, returning it back for convenient chaining.
+    fn stage(&self, chunk: Chunk) -> Chunk {
+        let hash = Hash::of(chunk.data());
+        self.chunks.borrow_mut().entry(hash).or_insert((chunk.clone(), Staged::Dirty));
+        chunk
+    }
+
+    /// All chunks not yet flushed to the store.
+    fn dirty(&self) -> Vec<(Hash, Chunk)> {
+        self.chunks.borrow().iter()
+            .filter(|&(_, &(_, staged))| staged == Staged::Dirty)
+            .map(|(hash, &(ref chunk, _))| (*hash, chunk.clone()))
+            .collect()
+    }
+
+    /// Marks `hash` as flushed, so it is no longer re-sent to the store.
+    fn mark_clean(&self, hash: Hash) {
+        if let Some(entry) = self.chunks.borrow_mut().get_mut(&hash) {
+            entry.1 = Staged::Clean;
+        }
+    }
+}
+
+/// Adapts the HTTP `Client` to the generic `ChunkStore` interface, driving
+/// each request to completion against the shared event loop.
+pub(crate) struct HttpChunkStore {
+    client: Client,
+    noms: Rc<RefCell<InnerNoms>>,
+}
+
+impl HttpChunkStore {
+    pub fn new(client: Client, noms: Rc<RefCell<InnerNoms>>) -> Self {
+        Self{ client, noms }
+    }
+}
+
+impl ChunkStore for HttpChunkStore {
+    fn get(&self, hash: Hash) -> Result<Option<Chunk>, Error> {
+        self.noms.borrow_mut().event_loop.run(self.client.get_chunk(hash)).map(Some)
+    }
+
+    fn put(&self, chunk: Chunk) -> Result<Hash, Error> {
+        self.noms.borrow_mut().event_loop.run(self.client.post_chunk(chunk))
+    }
+}
+
 #[derive(Clone)]
 pub struct Database {
     database: String,
     version: String,
     client: Client,
-    root: Ref,
+    root: Rc<RefCell<Ref>>,
     noms: Rc<RefCell<InnerNoms>>,
+    // A `ChunkCache` wrapping the real backend, so every `Database` clone
+    // shares one cache instead of each getting its own, empty one.
+    store: Rc<ChunkStore>,
+    overlay: Overlay,
 }
 
 impl Database {
-    pub(super) fn new(noms: Rc<RefCell<InnerNoms>>, database: String, version: String) -> Result<Self, Error> {
+    pub(super) fn new<S: ChunkStore + 'static>(noms: Rc<RefCell<InnerNoms>>, store: S, database: String, version: String) -> Result<Self, Error> {
         let client = Client::new(database.clone(), version.clone(), &noms.borrow().event_loop.handle());
         let get_root = client.get_root();
         let root = noms.borrow_mut().event_loop.run(get_root)?;
-        Ok(Self{ database, version, client, root, noms: noms.clone() })
+        Ok(Self{
+            database,
+            version,
+            client,
+            root: Rc::new(RefCell::new(root)),
+            noms: noms.clone(),
+            store: Rc::new(ChunkCache::new(Box::new(store))),
+            overlay: Overlay::new(),
+        })
+    }
+
+    /// Resolves `r` to its backing `Chunk`, going through the chunk cache
+    /// (`store` is a `ChunkCache` wrapping the real backend) before falling
+    /// back to it. Since chunks are immutable, this makes repeated
+    /// traversal of the same ref (e.g. walking dataset history) free after
+    /// the first fetch.
+    pub(super) fn resolve(&self, r: &Ref) -> Result<Chunk, Error> {
+        let hash = r.hash();
+        self.store.get(hash)?.ok_or_else(|| Error::MissingChunk(hash))
+    }
+
+    /// Reads the `Value` referenced by `r`, resolving it through the chunk
+    /// cache rather than always hitting the store directly.
+    pub fn read_ref(&self, r: &Ref) -> Result<Value, Error> {
+        self.resolve(r).map(Chunk::into_value)
+    }
+
+    /// Writes every chunk staged since the last flush to the store,
+    /// marking each clean once it has been written.
+    fn flush(&self) -> Result<(), Error> {
+        for (hash, chunk) in self.overlay.dirty() {
+            self.store.put(chunk)?;
+            self.overlay.mark_clean(hash);
+        }
+        Ok(())
+    }
+
+    fn encode_parents(parents: &HashSet<Ref>) -> Chunk {
+        let mut writer = Chunk::writer()
+            .write_kind(Kind::Set)
+            .write_u8(0)
+            .write_u32(parents.len() as u32);
+        for r in parents {
+            writer = writer.write_ref(r);
+        }
+        writer.finish()
+    }
+
+    fn encode_meta(meta: &HashMap<String, Value>) -> Chunk {
+        let props = meta.iter().map(|(k, v)| (k.clone(), v.0.clone())).collect();
+        Chunk::writer().write_struct("Meta", &props).finish()
+    }
+
+    /// Builds a new `Commit` over `value` with the given `parents`/`meta`,
+    /// stages every chunk it reaches (the value, the parent set, the meta
+    /// struct and the commit itself) as dirty, flushes them all to the
+    /// store, and returns the commit's content hash.
+    fn stage_commit(&self, value: Value, parents: &HashSet<Ref>, meta: &HashMap<String, Value>) -> Result<Hash, Error> {
+        let value_chunk = self.overlay.stage(value.0);
+        let parents_chunk = self.overlay.stage(Self::encode_parents(parents));
+        let meta_chunk = self.overlay.stage(Self::encode_meta(meta));
+        let commit_chunk = self.overlay.stage(Commit::new(meta_chunk, parents_chunk, value_chunk).encode());
+        self.flush()?;
+        Ok(Hash::of(commit_chunk.data()))
+    }
+
+    /// Optimistically moves the dataset's root ref from `expected` to
+    /// `new`. Errors (rather than retrying) if the store observed a
+    /// different root in the meantime.
+    fn cas_root(&self, expected: Hash, new: Hash) -> Result<Hash, Error> {
+        let client = &self.client;
+        let noms = &self.noms;
+        noms.borrow_mut().event_loop.run(client.post_root(expected, new))
+    }
+
+    /// Resolves the current root to the `Map<String, Ref>` of dataset heads
+    /// it points at, or an empty map if no root chunk has been written yet.
+    fn root_map(&self) -> Result<HashMap<String, Ref>, Error> {
+        let root = *self.root.borrow();
+        if root.is_empty() {
+            Ok(HashMap::new())
+        } else {
+            self.resolve(&root)?.reader().extract_map()
+        }
+    }
+
+    /// Stages and flushes `map` as a new root chunk, returning its content
+    /// hash for use as the `new` side of a `cas_root` call.
+    fn write_root_map(&self, map: &HashMap<String, Ref>) -> Result<Hash, Error> {
+        let chunk = self.overlay.stage(Chunk::writer().write_map(map).finish());
+        let hash = Hash::of(chunk.data());
+        self.flush()?;
+        Ok(hash)
+    }
+
+    /// Returns whether `ancestor` is `head` itself or reachable by walking
+    /// `head`'s commit chain through its `parents` set. Used by
+    /// `fast_forward` to reject a `head` that doesn't actually descend from
+    /// the current root, unlike `set_head` which moves there unconditionally.
+    fn is_descendant(&self, head: Hash, ancestor: Hash) -> Result<bool, Error> {
+        let mut frontier = VecDeque::new();
+        frontier.push_back(head);
+        let mut seen = HashSet::new();
+        while let Some(hash) = frontier.pop_front() {
+            if hash == ancestor {
+                return Ok(true);
+            }
+            if !seen.insert(hash) {
+                continue;
+            }
+            let commit = self.resolve(&Ref::new(hash))?;
+            let (_, props) = commit.reader().extract_struct();
+            if let Some(parents) = props.get("parents") {
+                let parents: HashSet<Ref> = parents.reader().extract_set()?;
+                frontier.extend(parents.into_iter().map(|r| r.hash()));
+            }
+        }
+        Ok(false)
     }
 }
 
 impl super::Database for Database {
     fn datasets(&self) -> Result<HashMap<String, Ref>, Error> {
-        if self.root.is_empty() {
+        let root = *self.root.borrow();
+        if root.is_empty() {
             Ok(HashMap::new())
         } else {
             self.noms
                 .borrow_mut()
                 .event_loop
-                .run(self.client.post_get_refs(&self.root, vec![self.root.clone()]))
+                .run(self.client.post_get_refs(&root, vec![root]))
                 .map(|v| unimplemented!())
         }
     }
     fn dataset<'a>(&'a self, ds: String) -> Dataset<'a> {
         Dataset::new(self, ds)
     }
-    fn rebase(&self) { unimplemented!() }
-    fn commit(&self, ds: Dataset, v: Value, o: CommitOptions) -> Result<Dataset, Error> { unimplemented!() }
-    fn commit_value(&self, ds: Dataset, v: Value) -> Result<Dataset, Error> { unimplemented!() }
-    fn delete(&self, ds: Dataset) -> Result<Dataset, Error> { unimplemented!() }
-    fn set_head(&self, ds: Dataset, head: Ref) -> Result<Dataset, Error> { unimplemented!() }
-    fn fast_forward(&self, ds: Dataset, head: Ref) -> Result<Dataset, Error> { unimplemented!() }
+
+    /// Refreshes the locally cached root ref from the server, so a commit
+    /// that lost its optimistic compare-and-set can be retried against
+    /// the current state.
+    fn rebase(&self) {
+        if let Ok(root) = self.noms.borrow_mut().event_loop.run(self.client.get_root()) {
+            *self.root.borrow_mut() = root;
+        }
+    }
+
+    fn commit(&self, ds: Dataset, v: Value, o: CommitOptions) -> Result<Dataset, Error> {
+        let expected = self.root.borrow().hash();
+        let new_head = self.stage_commit(v, &o.parents, &o.meta)?;
+        let mut datasets = self.root_map()?;
+        datasets.insert(ds.name().to_string(), Ref::new(new_head));
+        let new_root = self.write_root_map(&datasets)?;
+        match self.cas_root(expected, new_root) {
+            Ok(root_hash) => {
+                *self.root.borrow_mut() = Ref::new(root_hash);
+                Ok(ds)
+            }
+            Err(e) => {
+                self.rebase();
+                Err(e)
+            }
+        }
+    }
+
+    fn commit_value(&self, ds: Dataset, v: Value) -> Result<Dataset, Error> {
+        self.commit(ds, v, CommitOptions::default())
+    }
+
+    /// Removes only `ds`'s entry from the root dataset map, leaving every
+    /// other dataset untouched.
+    fn delete(&self, ds: Dataset) -> Result<Dataset, Error> {
+        let expected = self.root.borrow().hash();
+        let mut datasets = self.root_map()?;
+        datasets.remove(ds.name());
+        let new_root = self.write_root_map(&datasets)?;
+        let root_hash = self.cas_root(expected, new_root)?;
+        *self.root.borrow_mut() = Ref::new(root_hash);
+        Ok(ds)
+    }
+
+    /// Forcibly moves the dataset head to `head`, without requiring it to
+    /// be a descendant of the current root.
+    fn set_head(&self, ds: Dataset, head: Ref) -> Result<Dataset, Error> {
+        let expected = self.root.borrow().hash();
+        let mut datasets = self.root_map()?;
+        datasets.insert(ds.name().to_string(), head);
+        let new_root = self.write_root_map(&datasets)?;
+        let root_hash = self.cas_root(expected, new_root)?;
+        *self.root.borrow_mut() = Ref::new(root_hash);
+        Ok(ds)
+    }
+
+    /// Moves the dataset head to `head`, erroring rather than retrying if
+    /// the root has diverged since it was last observed. Unlike `commit`,
+    /// a failed compare-and-set here leaves the cached root as it was
+    /// rather than refreshing it. Unlike `set_head`, this also rejects `head` outright if
+    /// it isn't actually a descendant of the dataset's current head (a
+    /// dataset with no current head accepts any `head`, same as creating a
+    /// new branch).
+    fn fast_forward(&self, ds: Dataset, head: Ref) -> Result<Dataset, Error> {
+        let expected = self.root.borrow().hash();
+        let mut datasets = self.root_map()?;
+        if let Some(current) = datasets.get(ds.name()) {
+            if !self.is_descendant(head.hash(), current.hash())? {
+                return Err(Error::NotFastForward);
+            }
+        }
+        datasets.insert(ds.name().to_string(), head);
+        let new_root = self.write_root_map(&datasets)?;
+        let root_hash = self.cas_root(expected, new_root)?;
+        *self.root.borrow_mut() = Ref::new(root_hash);
+        Ok(ds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn staged_chunks_start_dirty_and_become_clean_once_flushed() {
+        let overlay = Overlay::new();
+        let chunk = Chunk::new(vec![1, 2, 3]);
+        let hash = Hash::of(chunk.data());
+
+        let staged = overlay.stage(chunk.clone());
+        assert_eq!(chunk, staged);
+        assert_eq!(vec![(hash, chunk)], overlay.dirty());
+
+        overlay.mark_clean(hash);
+        assert!(overlay.dirty().is_empty());
+    }
+
+    #[test]
+    fn restaging_an_already_staged_chunk_is_a_no_op() {
+        let overlay = Overlay::new();
+        let chunk = Chunk::new(vec![4, 5, 6]);
+        let hash = Hash::of(chunk.data());
+
+        overlay.stage(chunk.clone());
+        overlay.mark_clean(hash);
+        overlay.stage(chunk);
+
+        assert!(overlay.dirty().is_empty());
+    }
 }
@@ -1,34 +1,38 @@
-use byteorder::{NetworkEndian, ByteOrder};
 use std::collections::HashMap;
-use super::Value;
+use super::{Value, Number, Ref};
 use chunk::Chunk;
+use error::Error;
 
 pub trait IntoNoms {
     fn into_noms(&self) -> Value;
 }
-pub trait FromNoms {
-    fn from_noms(&Value) -> Self;
+pub trait FromNoms: Sized {
+    fn from_noms(&Value) -> Result<Self, Error>;
 }
 
 impl IntoNoms for Chunk {
     fn into_noms(&self) -> Value { self.clone().into_value() }
 }
 impl FromNoms for Chunk {
-    fn from_noms(v: &Value) -> Chunk { v.0.clone() }
+    fn from_noms(v: &Value) -> Result<Chunk, Error> { Ok(v.0.clone()) }
 }
 
 impl<T: IntoNoms> IntoNoms for Vec<T> {
     fn into_noms(&self) -> Value {
-        let mut buf = [0; 4];
-        NetworkEndian::write_u32(&mut buf, self.len() as u32);
-        let mut val = buf.to_vec();
-        val.extend(self.iter().flat_map(|v| v.into_noms().into_raw().into_iter()));
-        Value(Chunk::new(val))
+        Chunk::writer()
+            .write_list(self)
+            .finish()
+            .into_value()
+    }
+}
+impl<T: FromNoms> FromNoms for Vec<T> {
+    fn from_noms(v: &Value) -> Result<Self, Error> {
+        v.0.reader().extract_list()
     }
 }
 
 impl<K: FromNoms + Eq + ::std::hash::Hash, V: FromNoms> FromNoms for HashMap<K, V> {
-    fn from_noms(v: &Value) -> Self {
+    fn from_noms(v: &Value) -> Result<Self, Error> {
         v.0.reader().extract_map()
     }
 }
@@ -42,9 +46,23 @@ impl<K: IntoNoms + Eq + ::std::hash::Hash, V: IntoNoms> IntoNoms for HashMap<K,
     }
 }
 
+impl FromNoms for Ref {
+    fn from_noms(v: &Value) -> Result<Self, Error> {
+        Ok(v.0.reader().extract_ref())
+    }
+}
+impl IntoNoms for Ref {
+    fn into_noms(&self) -> Value {
+        Chunk::writer()
+            .write_ref(self)
+            .finish()
+            .into_value()
+    }
+}
+
 impl FromNoms for String {
-    fn from_noms(v: &Value) -> Self {
-        v.0.reader().extract_string()
+    fn from_noms(v: &Value) -> Result<Self, Error> {
+        Ok(v.0.reader().extract_string())
     }
 }
 impl IntoNoms for String {
@@ -55,3 +73,69 @@ impl IntoNoms for String {
             .into_value()
     }
 }
+
+impl FromNoms for Number {
+    fn from_noms(v: &Value) -> Result<Self, Error> {
+        Ok(v.0.reader().extract_number())
+    }
+}
+impl IntoNoms for Number {
+    fn into_noms(&self) -> Value {
+        Chunk::writer()
+            .write_number(self)
+            .finish()
+            .into_value()
+    }
+}
+
+impl FromNoms for i64 {
+    fn from_noms(v: &Value) -> Result<Self, Error> {
+        Ok(Number::from_noms(v)?.to_i64())
+    }
+}
+impl IntoNoms for i64 {
+    fn into_noms(&self) -> Value {
+        Number::from(*self).into_noms()
+    }
+}
+
+impl FromNoms for u64 {
+    fn from_noms(v: &Value) -> Result<Self, Error> {
+        Ok(Number::from_noms(v)?.to_u64())
+    }
+}
+impl IntoNoms for u64 {
+    fn into_noms(&self) -> Value {
+        Number::from(*self).into_noms()
+    }
+}
+
+impl FromNoms for f64 {
+    fn from_noms(v: &Value) -> Result<Self, Error> {
+        Ok(Number::from_noms(v)?.to_f64())
+    }
+}
+impl IntoNoms for f64 {
+    fn into_noms(&self) -> Value {
+        Number::from(*self).into_noms()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vec_of_i64_round_trips_through_noms() {
+        let values: Vec<i64> = vec![-9_007_199_254_740_993, 0, 42];
+        let decoded: Vec<i64> = FromNoms::from_noms(&values.into_noms()).unwrap();
+        assert_eq!(values, decoded);
+    }
+
+    #[test]
+    fn vec_of_strings_round_trips_through_noms() {
+        let values: Vec<String> = vec!["a".to_string(), "bb".to_string()];
+        let decoded: Vec<String> = FromNoms::from_noms(&values.into_noms()).unwrap();
+        assert_eq!(values, decoded);
+    }
+}
@@ -1,9 +1,11 @@
 //! Generic representation of a value in the database
 
 mod conversion;
+mod number;
+pub use self::number::Number;
 use std::collections::{HashMap, HashSet};
 use hash::{Hash, EMPTY_HASH};
-use chunk::Chunk;
+use chunk::{Chunk, ChunkStore, SequenceIter, MapSequenceIter};
 
 pub enum Type {
     Boolean,
@@ -50,6 +52,25 @@ impl Value {
     pub fn into_raw(self) -> Vec<u8> {
         self.0.into_data()
     }
+
+    /// Lazily iterates the elements of a `Set` or `List` value, resolving
+    /// child chunks of a sequence tree through `store` only as the iterator
+    /// advances, rather than materializing the whole collection up front.
+    pub fn iter<'a>(&self, store: &'a ChunkStore) -> SequenceIter<'a> {
+        let reader = self.0.reader();
+        reader.extract_kind();
+        let sequence = reader.extract_sequence(1);
+        SequenceIter::new(store, sequence)
+    }
+
+    /// Lazily iterates the entries of a `Map` value as `(key, value)`
+    /// pairs, with the same lazy sequence-tree traversal as `iter`.
+    pub fn iter_entries<'a>(&self, store: &'a ChunkStore) -> MapSequenceIter<'a> {
+        let reader = self.0.reader();
+        reader.extract_kind();
+        let sequence = reader.extract_sequence(2);
+        MapSequenceIter::new(store, sequence)
+    }
 }
 
 #[derive(Debug)]
@@ -58,6 +79,23 @@ pub struct Commit {
     parents: Chunk,
     value: Chunk,
 }
+impl Commit {
+    pub(crate) fn new(meta: Chunk, parents: Chunk, value: Chunk) -> Self {
+        Self{ meta, parents, value }
+    }
+
+    /// Encodes this commit as a `Struct("Commit", { meta, parents, value })`
+    /// chunk, matching the layout `ChunkReader::extract_struct` expects.
+    pub(crate) fn encode(&self) -> Chunk {
+        let mut props = HashMap::with_capacity(3);
+        props.insert("meta".to_string(), self.meta.clone());
+        props.insert("parents".to_string(), self.parents.clone());
+        props.insert("value".to_string(), self.value.clone());
+        Chunk::writer()
+            .write_struct("Commit", &props)
+            .finish()
+    }
+}
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct Ref {
@@ -0,0 +1,134 @@
+//! Arbitrary-precision `Number` values
+//!
+//! A Noms number is an arbitrary-precision integer mantissa together with a
+//! base-10 exponent (`mantissa * 10^exponent`), so it can represent both
+//! huge integers and small fractions exactly, unlike a fixed-width machine
+//! type.
+
+use num_bigint::{BigInt, Sign};
+use num_traits::ToPrimitive;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Number {
+    mantissa: BigInt,
+    exponent: i32,
+}
+
+impl Number {
+    pub fn new(mantissa: BigInt, exponent: i32) -> Self {
+        Self{ mantissa, exponent }
+    }
+
+    pub fn mantissa(&self) -> &BigInt {
+        &self.mantissa
+    }
+
+    pub fn exponent(&self) -> i32 {
+        self.exponent
+    }
+
+    /// The mantissa's sign and big-endian magnitude, as written to a chunk.
+    pub(crate) fn to_be_bytes(&self) -> (bool, Vec<u8>) {
+        let (sign, bytes) = self.mantissa.to_bytes_be();
+        (sign == Sign::Minus, bytes)
+    }
+
+    /// Reconstructs a `Number` from the sign/magnitude/exponent written by
+    /// `to_be_bytes`.
+    pub(crate) fn from_be_bytes(negative: bool, bytes: &[u8], exponent: i32) -> Self {
+        let magnitude = BigInt::from_bytes_be(Sign::Plus, bytes);
+        let mantissa = if negative { -magnitude } else { magnitude };
+        Number::new(mantissa, exponent)
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let mantissa: f64 = self.mantissa.to_string().parse().unwrap_or(0.0);
+        mantissa * 10f64.powi(self.exponent)
+    }
+
+    /// `mantissa` scaled by `10^exponent`, computed over `BigInt` so a
+    /// `Number` built from an `i64`/`u64` (always `exponent == 0`) round
+    /// trips exactly instead of losing precision through an `f64`
+    /// intermediate above 2^53. A negative `exponent` truncates towards
+    /// zero, same as integer division.
+    fn to_scaled_bigint(&self) -> BigInt {
+        if self.exponent >= 0 {
+            self.mantissa.clone() * Self::pow10(self.exponent as u32)
+        } else {
+            self.mantissa.clone() / Self::pow10((-self.exponent) as u32)
+        }
+    }
+
+    fn pow10(exponent: u32) -> BigInt {
+        let mut result = BigInt::from(1);
+        for _ in 0..exponent {
+            result *= 10;
+        }
+        result
+    }
+
+    pub fn to_i64(&self) -> i64 {
+        self.to_scaled_bigint().to_i64().unwrap_or(0)
+    }
+
+    pub fn to_u64(&self) -> u64 {
+        self.to_scaled_bigint().to_u64().unwrap_or(0)
+    }
+}
+
+impl From<i64> for Number {
+    fn from(v: i64) -> Self {
+        Number::new(BigInt::from(v), 0)
+    }
+}
+
+impl From<u64> for Number {
+    fn from(v: u64) -> Self {
+        Number::new(BigInt::from(v), 0)
+    }
+}
+
+impl From<f64> for Number {
+    fn from(v: f64) -> Self {
+        let formatted = format!("{:e}", v);
+        let mut parts = formatted.splitn(2, 'e');
+        let digits = parts.next().unwrap_or("0");
+        let exp: i32 = parts.next().and_then(|e| e.parse().ok()).unwrap_or(0);
+
+        let (negative, digits) = match digits.as_bytes().get(0) {
+            Some(b'-') => (true, &digits[1..]),
+            _ => (false, digits),
+        };
+        let mut digit_parts = digits.splitn(2, '.');
+        let int_part = digit_parts.next().unwrap_or("0");
+        let frac_part = digit_parts.next().unwrap_or("");
+
+        let magnitude: BigInt = format!("{}{}", int_part, frac_part).parse().unwrap_or_else(|_| BigInt::from(0));
+        let mantissa = if negative { -magnitude } else { magnitude };
+        Number::new(mantissa, exp - frac_part.len() as i32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_i64_round_trips_past_f64s_precision_limit() {
+        // 2^53 + 1, the smallest i64 an f64 intermediate cannot represent
+        let n = 9_007_199_254_740_993_i64;
+        assert_eq!(n, Number::from(n).to_i64());
+    }
+
+    #[test]
+    fn to_u64_round_trips_past_f64s_precision_limit() {
+        let n = 9_007_199_254_740_993_u64;
+        assert_eq!(n, Number::from(n).to_u64());
+    }
+
+    #[test]
+    fn to_i64_round_trips_negative_values() {
+        let n = -9_007_199_254_740_993_i64;
+        assert_eq!(n, Number::from(n).to_i64());
+    }
+}